@@ -1,4 +1,4 @@
-use crate::{database::DatabaseGuard, Error, Result, Ruma};
+use crate::{database::DatabaseGuard, ratelimit, Error, Result, Ruma};
 use ruma::api::client::{
     error::ErrorKind,
     r0::filter::{create_filter, get_filter},
@@ -30,6 +30,14 @@ pub async fn create_filter_route(
     body: Ruma<create_filter::Request<'_>>,
 ) -> Result<create_filter::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if let Err(retry_after) = ratelimit::global().check(sender_user.as_str(), "create_filter") {
+        return Err(Error::RateLimited {
+            retry_after,
+            reason: "too many filters created, try again shortly",
+        });
+    }
+
     Ok(create_filter::Response::new(
         db.users.create_filter(sender_user, &body.filter)?,
     ))
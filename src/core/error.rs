@@ -1,4 +1,11 @@
-use std::{convert::Infallible, fmt};
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	convert::Infallible,
+	fmt,
+	hash::{Hash, Hasher},
+	sync::{Mutex, OnceLock},
+	time::{Duration, Instant},
+};
 
 use bytes::BytesMut;
 use http::StatusCode;
@@ -75,6 +82,22 @@ pub enum Error {
 	BadServerResponse(&'static str),
 	#[error("{0}")]
 	Conflict(&'static str), // This is only needed for when a room alias already exists
+	#[error("rate limited: {reason}")]
+	RateLimited { retry_after: Duration, reason: &'static str },
+
+	// conduwuit key-value backends
+	#[cfg(feature = "rocksdb")]
+	#[error("RocksDB error: {0}")]
+	RocksDbError(#[from] rocksdb::Error),
+	#[cfg(feature = "sqlite")]
+	#[error("SQLite error: {0}")]
+	SqliteError(#[from] rusqlite::Error),
+	#[cfg(feature = "heed")]
+	#[error("LMDB error: {0}")]
+	HeedError(#[from] heed::Error),
+	#[cfg(feature = "persy")]
+	#[error("Persy error: {0}")]
+	PersyError(#[from] persy::PersyError),
 
 	// unique / untyped
 	#[error("{0}")]
@@ -95,25 +118,65 @@ impl Error {
 	/// Returns the Matrix error code / error kind
 	#[inline]
 	pub fn error_code(&self) -> ruma::api::client::error::ErrorKind {
-		use ruma::api::client::error::ErrorKind::Unknown;
+		use ruma::api::client::error::ErrorKind::{LimitExceeded, Unknown};
 
 		match self {
 			Self::Federation(_, err) => err.error_kind().unwrap_or(&Unknown).clone(),
 			Self::BadRequest(kind, _) => kind.clone(),
+			Self::RateLimited { retry_after, .. } => LimitExceeded {
+				retry_after_ms: Some(*retry_after),
+			},
 			_ => Unknown,
 		}
 	}
 
+	/// Classifies whether this error's `Display` is safe to return to a
+	/// client verbatim, or whether it may contain internal detail (paths,
+	/// server names, config/regex fragments) that must be redacted first.
+	///
+	/// See [`Visibility`] and [`sanitized_error`](Self::sanitized_error).
+	#[inline]
+	pub fn visibility(&self) -> Visibility {
+		match self {
+			Self::BadRequest(..)
+			| Self::Conflict(..)
+			| Self::RateLimited { .. }
+			| Self::Mxid(..)
+			| Self::InconsistentRoomState(..)
+			| Self::Federation(..)
+			| Self::Redaction(..)
+			| Self::Uiaa(..) => Visibility::Client,
+			_ => Visibility::Internal,
+		}
+	}
+
 	/// Sanitizes public-facing errors that can leak sensitive information.
+	///
+	/// [`Visibility::Client`] errors pass through verbatim; everything else
+	/// collapses to a single generic message, with the real detail left for
+	/// the caller to log alongside a correlation id (see
+	/// [`UiaaResponse`](From<Error>)).
 	pub fn sanitized_error(&self) -> String {
-		match self {
-			Self::Database(..) => String::from("Database error occurred."),
-			Self::Io(..) => String::from("I/O error occurred."),
-			_ => self.to_string(),
+		match self.visibility() {
+			Visibility::Client => self.to_string(),
+			Visibility::Internal => String::from("Internal server error occurred."),
 		}
 	}
 }
 
+/// Whether an [`Error`]'s message is safe to show a client, or must be
+/// redacted because it may carry internal detail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+	/// The `Display` string is meaningful and safe for the client to see.
+	Client,
+	/// The `Display` string may leak internal detail; only a generic
+	/// message is shown to the client. When the detail is logged
+	/// server-side, the message carries a correlation id for that log line;
+	/// a duplicate suppressed by the log throttle gets neither.
+	Internal,
+}
+
 impl From<Infallible> for Error {
 	fn from(i: Infallible) -> Self { match i {} }
 }
@@ -124,14 +187,35 @@ impl fmt::Debug for Error {
 
 impl axum::response::IntoResponse for Error {
 	fn into_response(self) -> axum::response::Response {
+		let retry_after = match &self {
+			Self::RateLimited { retry_after, .. } => Some(*retry_after),
+			_ => None,
+		};
+
 		let response: UiaaResponse = self.into();
-		response.try_into_http_response::<BytesMut>().map_or_else(
+		let mut response = response.try_into_http_response::<BytesMut>().map_or_else(
 			|_| StatusCode::INTERNAL_SERVER_ERROR.into_response(),
 			|r| r.map(BytesMut::freeze).map(Full::new).into_response(),
-		)
+		);
+
+		if let Some(retry_after) = retry_after {
+			let secs = retry_after_secs(retry_after);
+			if let Ok(value) = http::HeaderValue::from_str(&secs.to_string()) {
+				response.headers_mut().insert(http::header::RETRY_AFTER, value);
+			}
+		}
+
+		response
 	}
 }
 
+/// Rounds up to the next whole second so a sub-second `retry_after` (which
+/// `RateLimiter::check`'s token-bucket math routinely produces) doesn't
+/// floor to `Retry-After: 0` and invite an immediate retry.
+fn retry_after_secs(retry_after: Duration) -> u64 {
+	retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
 impl From<Error> for UiaaResponse {
 	fn from(error: Error) -> Self {
 		use ruma::api::client::error::{Error as RumaError, ErrorBody, ErrorKind::Unknown};
@@ -140,27 +224,41 @@ impl From<Error> for UiaaResponse {
 			return Self::AuthResponse(uiaainfo);
 		}
 
-		let kind = match &error {
-			Error::Federation(_, ref error) => error.error_kind().unwrap_or(&Unknown),
-			Error::BadRequest(kind, _) => kind,
-			_ => &Unknown,
-		};
+		let kind = error.error_code();
 
 		let status_code = match &error {
 			Error::Federation(_, ref error) => error.status_code,
 			Error::BadRequest(ref kind, _) => bad_request_code(kind),
 			Error::Conflict(_) => StatusCode::CONFLICT,
+			Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
 			_ => StatusCode::INTERNAL_SERVER_ERROR,
 		};
 
-		let message = if let Error::Federation(ref origin, ref error) = &error {
-			format!("Answer from {origin}: {error}")
-		} else {
-			format!("{error}")
+		let message = match error.visibility() {
+			Visibility::Client =>
+				if let Error::Federation(ref origin, ref error) = &error {
+					format!("Answer from {origin}: {error}")
+				} else {
+					format!("{error}")
+				},
+			Visibility::Internal => {
+				let request_id = next_correlation_id();
+				if log_gated(&error, Some(&request_id)) {
+					format!("Internal server error. Include this id when reporting: {request_id}")
+				} else {
+					// A bucket-mate was already logged within the throttle window; this
+					// occurrence wasn't written anywhere, so handing out an id would
+					// send the caller grepping for a line that doesn't exist.
+					String::from(
+						"Internal server error occurred. This is a duplicate of a recently \
+						 logged error; no new log entry was written for this request.",
+					)
+				}
+			},
 		};
 
 		let body = ErrorBody::Standard {
-			kind: kind.clone(),
+			kind,
 			message,
 		};
 
@@ -204,14 +302,269 @@ fn bad_request_code(kind: &ruma::api::client::error::ErrorKind) -> StatusCode {
 	}
 }
 
+/// Generates an opaque id to correlate an internal error logged
+/// server-side with the generic message a client sees, so an operator can
+/// grep the logs for the exact failing request.
+///
+/// Deliberately not a bare incrementing counter: handing clients a
+/// sequential id would let them infer the server's total internal-error
+/// rate across all tenants by watching it jump between their own
+/// requests. A monotonic counter still feeds this (so no two calls hash the
+/// same input within a process lifetime), but it's mixed with the time and
+/// pid before being hashed into 64 bits, so the output itself carries no
+/// order; distinct inputs can still, rarely, hash to the same id.
+fn next_correlation_id() -> String {
+	use std::{
+		sync::atomic::{AtomicU64, Ordering},
+		time::{SystemTime, UNIX_EPOCH},
+	};
+
+	static COUNTER: AtomicU64 = AtomicU64::new(0);
+	let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+
+	let mut hasher = DefaultHasher::new();
+	counter.hash(&mut hasher);
+	nanos.hash(&mut hasher);
+	std::process::id().hash(&mut hasher);
+	format!("err-{:016x}", hasher.finish())
+}
+
+/// Logs an error through `error!`, throttling bursts of identical messages.
+/// See [`throttle`]; use [`log_unthrottled`] to bypass it.
 #[inline]
 pub fn log(e: Error) {
-	error!("{e}");
+	let _emitted = log_gated(&e, None);
 	drop(e);
 }
 
+/// Shared `error!` + [`throttle::gate`] path for [`log`] and the
+/// internal-error branch of `From<Error> for UiaaResponse`. `suffix` is
+/// appended to the line actually emitted, omitted for suppressed duplicates.
+/// Returns whether a line was actually written, so callers that hand a
+/// correlation id to the client can tell whether it's grep-able.
+fn log_gated(e: &Error, suffix: Option<&str>) -> bool {
+	let suffix = suffix.map(|s| format!(" (request_id: {s})")).unwrap_or_default();
+	match throttle::gate(e) {
+		throttle::Decision::Emit => {
+			error!("{e}{suffix}");
+			true
+		},
+		throttle::Decision::Suppressed => false,
+		throttle::Decision::Summary(suppressed) => {
+			error!(
+				"{e}{suffix} ({suppressed} identical error{s} suppressed in the last {window}ms)",
+				s = if suppressed == 1 { "" } else { "s" },
+				window = throttle::window().as_millis(),
+			);
+			true
+		},
+	}
+}
+
 #[inline]
 pub fn debug_log(e: Error) {
-	debug_error!("{e}");
+	match throttle::gate(&e) {
+		throttle::Decision::Emit => debug_error!("{e}"),
+		throttle::Decision::Suppressed => {},
+		throttle::Decision::Summary(suppressed) => debug_error!(
+			"{e} ({suppressed} identical error{s} suppressed in the last {window}ms)",
+			s = if suppressed == 1 { "" } else { "s" },
+			window = throttle::window().as_millis(),
+		),
+	}
+	drop(e);
+}
+
+/// Logs an error through `error!`, bypassing the throttle in [`log`].
+#[inline]
+pub fn log_unthrottled(e: Error) {
+	error!("{e}");
 	drop(e);
 }
+
+/// Log throttling / deduplication for [`log`] and [`debug_log`].
+mod throttle {
+	use super::{DefaultHasher, Duration, Error, Hash, Hasher, HashMap, Instant, Mutex, OnceLock};
+
+	/// Default width of a suppression window; overridable via
+	/// `CONDUWUIT_LOG_THROTTLE_WINDOW_MS`.
+	const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+	/// Default number of occurrences emitted before later ones in the same
+	/// window are suppressed; overridable via
+	/// `CONDUWUIT_LOG_THROTTLE_BURST_LIMIT`.
+	const DEFAULT_BURST_LIMIT: u32 = 1;
+
+	/// Hard cap on distinct buckets kept at once; overridable via
+	/// `CONDUWUIT_LOG_THROTTLE_MAX_ENTRIES`.
+	const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+	/// A bucket is swept once its window has been closed this many times
+	/// over without a fresh occurrence.
+	const STALE_FACTOR: u32 = 4;
+
+	struct Bucket {
+		window_start: Instant,
+		count: u32,
+	}
+
+	static BUCKETS: OnceLock<Mutex<HashMap<u64, Bucket>>> = OnceLock::new();
+
+	pub(super) enum Decision {
+		/// Emit the message as normal.
+		Emit,
+		/// An identical message was already emitted this window.
+		Suppressed,
+		/// The window rolled over; emit a summary of what was suppressed.
+		Summary(u32),
+	}
+
+	pub(super) fn window() -> Duration {
+		static WINDOW: OnceLock<Duration> = OnceLock::new();
+		*WINDOW.get_or_init(|| {
+			std::env::var("CONDUWUIT_LOG_THROTTLE_WINDOW_MS")
+				.ok()
+				.and_then(|v| v.parse().ok())
+				.map(Duration::from_millis)
+				.unwrap_or(DEFAULT_WINDOW)
+		})
+	}
+
+	fn burst_limit() -> u32 {
+		static BURST_LIMIT: OnceLock<u32> = OnceLock::new();
+		*BURST_LIMIT.get_or_init(|| {
+			std::env::var("CONDUWUIT_LOG_THROTTLE_BURST_LIMIT")
+				.ok()
+				.and_then(|v| v.parse().ok())
+				.unwrap_or(DEFAULT_BURST_LIMIT)
+		})
+	}
+
+	fn max_entries() -> usize {
+		static MAX_ENTRIES: OnceLock<usize> = OnceLock::new();
+		*MAX_ENTRIES.get_or_init(|| {
+			std::env::var("CONDUWUIT_LOG_THROTTLE_MAX_ENTRIES")
+				.ok()
+				.and_then(|v| v.parse().ok())
+				.unwrap_or(DEFAULT_MAX_ENTRIES)
+		})
+	}
+
+	fn key_for(e: &Error) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		e.to_string().hash(&mut hasher);
+		// ErrorKind isn't `Hash`; its Debug output is stable enough to
+		// distinguish otherwise-identical messages with different kinds.
+		format!("{:?}", e.error_code()).hash(&mut hasher);
+		hasher.finish()
+	}
+
+	/// Evicts buckets that have gone stale, then (if still over capacity)
+	/// the single oldest bucket, so a steady trickle of distinct messages
+	/// (e.g. ones carrying a room id or driver-specific detail) can't grow
+	/// this map without bound.
+	fn evict(buckets: &mut HashMap<u64, Bucket>, window: Duration, now: Instant) {
+		let stale_after = window.saturating_mul(STALE_FACTOR);
+		buckets.retain(|_, bucket| now.duration_since(bucket.window_start) <= stale_after);
+
+		if buckets.len() >= max_entries() {
+			if let Some(&oldest) = buckets
+				.iter()
+				.min_by_key(|(_, bucket)| bucket.window_start)
+				.map(|(key, _)| key)
+			{
+				buckets.remove(&oldest);
+			}
+		}
+	}
+
+	pub(super) fn gate(e: &Error) -> Decision {
+		let key = key_for(e);
+		let window = window();
+		let buckets = BUCKETS.get_or_init(|| Mutex::new(HashMap::new()));
+		let mut buckets = buckets.lock().expect("log throttle mutex poisoned");
+		let now = Instant::now();
+
+		if !buckets.contains_key(&key) {
+			evict(&mut buckets, window, now);
+		}
+
+		match buckets.get_mut(&key) {
+			| None => {
+				buckets.insert(key, Bucket { window_start: now, count: 1 });
+				Decision::Emit
+			},
+			| Some(bucket) if now.duration_since(bucket.window_start) > window => {
+				let suppressed = bucket.count.saturating_sub(burst_limit());
+				bucket.window_start = now;
+				bucket.count = 1;
+				if suppressed > 0 {
+					Decision::Summary(suppressed)
+				} else {
+					Decision::Emit
+				}
+			},
+			| Some(bucket) => {
+				bucket.count = bucket.count.saturating_add(1);
+				if bucket.count <= burst_limit() {
+					Decision::Emit
+				} else {
+					Decision::Suppressed
+				}
+			},
+		}
+	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+
+		#[test]
+		fn bucket_evicts_when_over_capacity() {
+			let mut buckets = HashMap::new();
+			let now = Instant::now();
+			for key in 0..max_entries() as u64 {
+				buckets.insert(key, Bucket { window_start: now, count: 1 });
+			}
+
+			evict(&mut buckets, DEFAULT_WINDOW, now);
+
+			assert!(buckets.len() < max_entries());
+		}
+
+		#[test]
+		fn bucket_sweeps_stale_entries() {
+			let mut buckets = HashMap::new();
+			let now = Instant::now();
+			let stale = now - DEFAULT_WINDOW * (STALE_FACTOR + 1);
+			buckets.insert(1, Bucket { window_start: stale, count: 1 });
+
+			evict(&mut buckets, DEFAULT_WINDOW, now);
+
+			assert!(buckets.is_empty());
+		}
+	}
+}
+
+#[cfg(test)]
+mod retry_after_tests {
+	use super::*;
+
+	#[test]
+	fn rounds_up_sub_second_remainder() {
+		assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+		assert_eq!(retry_after_secs(Duration::from_millis(999)), 1);
+	}
+
+	#[test]
+	fn leaves_whole_seconds_untouched() {
+		assert_eq!(retry_after_secs(Duration::from_secs(0)), 0);
+		assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+	}
+
+	#[test]
+	fn rounds_up_seconds_plus_remainder() {
+		assert_eq!(retry_after_secs(Duration::from_millis(2500)), 3);
+	}
+}
@@ -0,0 +1,185 @@
+//! A lightweight token-bucket rate limiter keyed by `(sender_user,
+//! endpoint_class)`. The `Duration` [`RateLimiter::check`] returns is meant
+//! to be threaded straight into `Error::RateLimited`.
+
+use std::{
+	collections::HashMap,
+	sync::{Mutex, OnceLock},
+	time::{Duration, Instant},
+};
+
+/// Identifies a class of endpoint for rate limiting purposes, e.g.
+/// `"room_message"` or `"register"`. Kept as a `&'static str` rather than an
+/// enum so new classes can be added at call sites without touching this
+/// module.
+pub type EndpointClass = &'static str;
+
+/// Hard cap on distinct `(user, class)` buckets kept at once; overridable
+/// via `CONDUWUIT_RATELIMIT_MAX_ENTRIES`.
+const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+/// A bucket is swept once it's sat idle for this many multiples of its own
+/// full-refill time, i.e. long enough that it would be back at capacity
+/// anyway.
+const STALE_FACTOR: u32 = 4;
+
+struct Bucket {
+	tokens: f64,
+	last_refill: Instant,
+}
+
+/// A token-bucket limiter keyed by `(sender_user, endpoint_class)`.
+pub struct RateLimiter {
+	capacity: f64,
+	refill_per_sec: f64,
+	buckets: Mutex<HashMap<(String, EndpointClass), Bucket>>,
+}
+
+impl RateLimiter {
+	/// Creates a limiter that allows `capacity` requests to burst before
+	/// throttling, refilling at `refill_per_sec` tokens per second.
+	#[must_use]
+	pub fn new(capacity: f64, refill_per_sec: f64) -> Self {
+		Self {
+			capacity,
+			refill_per_sec,
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Attempts to consume one token for `(user, class)`.
+	///
+	/// Returns `Ok(())` if the request may proceed, or `Err(retry_after)`
+	/// with how long the caller should wait before the bucket refills
+	/// enough for another attempt.
+	pub fn check(&self, user: &str, class: EndpointClass) -> Result<(), Duration> {
+		let mut buckets = self.buckets.lock().expect("rate limiter mutex poisoned");
+		let now = Instant::now();
+		let key = (user.to_owned(), class);
+
+		if !buckets.contains_key(&key) {
+			self.evict(&mut buckets, now);
+		}
+
+		let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+			tokens: self.capacity,
+			last_refill: now,
+		});
+
+		let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+		bucket.last_refill = now;
+
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			Ok(())
+		} else {
+			let deficit = 1.0 - bucket.tokens;
+			Err(Duration::from_secs_f64(deficit / self.refill_per_sec))
+		}
+	}
+
+	/// Evicts buckets that have sat idle long enough to be stale, then (if
+	/// still over capacity) the single oldest bucket, so one limiter
+	/// instance shared by every user on a long-running homeserver can't
+	/// grow this map without bound.
+	fn evict(&self, buckets: &mut HashMap<(String, EndpointClass), Bucket>, now: Instant) {
+		let full_refill = Duration::from_secs_f64(self.capacity / self.refill_per_sec);
+		let stale_after = full_refill.saturating_mul(STALE_FACTOR).max(Duration::from_secs(1));
+		buckets.retain(|_, bucket| now.duration_since(bucket.last_refill) <= stale_after);
+
+		if buckets.len() >= max_entries() {
+			if let Some(oldest) = buckets
+				.iter()
+				.min_by_key(|(_, bucket)| bucket.last_refill)
+				.map(|(key, _)| key.clone())
+			{
+				buckets.remove(&oldest);
+			}
+		}
+	}
+}
+
+fn max_entries() -> usize {
+	static MAX_ENTRIES: OnceLock<usize> = OnceLock::new();
+	*MAX_ENTRIES.get_or_init(|| {
+		std::env::var("CONDUWUIT_RATELIMIT_MAX_ENTRIES")
+			.ok()
+			.and_then(|v| v.parse().ok())
+			.unwrap_or(DEFAULT_MAX_ENTRIES)
+	})
+}
+
+/// The process-wide limiter hot routes consult until per-service rate
+/// limiting state is threaded through properly.
+pub fn global() -> &'static RateLimiter {
+	static GLOBAL: OnceLock<RateLimiter> = OnceLock::new();
+	GLOBAL.get_or_init(|| RateLimiter::new(5.0, 0.5))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn allows_burst_up_to_capacity() {
+		let limiter = RateLimiter::new(2.0, 1.0);
+		assert!(limiter.check("alice", "room_message").is_ok());
+		assert!(limiter.check("alice", "room_message").is_ok());
+		assert!(limiter.check("alice", "room_message").is_err());
+	}
+
+	#[test]
+	fn tracks_users_and_classes_independently() {
+		let limiter = RateLimiter::new(1.0, 1.0);
+		assert!(limiter.check("alice", "room_message").is_ok());
+		assert!(limiter.check("bob", "room_message").is_ok());
+		assert!(limiter.check("alice", "register").is_ok());
+	}
+
+	#[test]
+	fn reports_a_sub_second_retry_after_when_exhausted() {
+		let limiter = RateLimiter::new(1.0, 1.0);
+		assert!(limiter.check("alice", "room_message").is_ok());
+		let retry_after = limiter.check("alice", "room_message").unwrap_err();
+		assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+	}
+
+	#[test]
+	fn evicts_stale_buckets_before_growing_unbounded() {
+		let limiter = RateLimiter::new(1.0, 1.0);
+		let now = Instant::now();
+		{
+			let mut buckets = limiter.buckets.lock().unwrap();
+			let stale = now - Duration::from_secs(1) * (STALE_FACTOR + 1);
+			buckets.insert(("stale-user".to_owned(), "room_message"), Bucket {
+				tokens: 1.0,
+				last_refill: stale,
+			});
+		}
+
+		// Any fresh check should sweep the stale entry out.
+		assert!(limiter.check("alice", "room_message").is_ok());
+		let buckets = limiter.buckets.lock().unwrap();
+		assert!(!buckets.contains_key(&("stale-user".to_owned(), "room_message")));
+	}
+
+	#[test]
+	fn evicts_oldest_bucket_once_over_capacity() {
+		let limiter = RateLimiter::new(1.0, 1.0);
+		let now = Instant::now();
+		{
+			let mut buckets = limiter.buckets.lock().unwrap();
+			for i in 0..max_entries() {
+				buckets.insert((format!("user-{i}"), "room_message"), Bucket {
+					tokens: 1.0,
+					last_refill: now,
+				});
+			}
+		}
+
+		assert!(limiter.check("new-user", "room_message").is_ok());
+		let buckets = limiter.buckets.lock().unwrap();
+		assert!(buckets.len() <= max_entries());
+	}
+}